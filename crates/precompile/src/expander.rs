@@ -1,34 +1,342 @@
-use std::io::{Cursor, Write};
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::sync::Arc;
 
 use arith::FieldSerde;
 use circuit::Circuit;
-use config::{BN254ConfigSha2, Config, GKRScheme, MPIConfig};
-use ethabi::ParamType;
+use config::{
+    BN254ConfigKeccak, BN254ConfigSha2, Config, GKRConfig, GKRScheme, MPIConfig,
+};
+use ethabi::{ParamType, Token};
 use expander::Verifier;
-use flate2::write::GzDecoder;
-use halo2curves::bn256::Fr;
-use revm_primitives::Bytes;
+use flate2::read::{DeflateDecoder, GzDecoder};
+use revm_primitives::{keccak256, Bytes, Env, B256};
 use transcript::Proof;
 
 use crate::{
     Precompile, PrecompileError, PrecompileErrors, PrecompileOutput, PrecompileResult,
-    PrecompileWithAddress,
+    PrecompileWithAddress, StatefulPrecompile,
 };
 
-pub const VERIFY_EXPANDER: PrecompileWithAddress = PrecompileWithAddress(
-    crate::u64_to_address(0xff02),
-    Precompile::Standard(verify_expander),
-);
+/// Build the `verify_expander` precompile bound to the side-chain blob registry
+/// the execution environment resolved from its data-availability layer for this
+/// block. The registry travels with the precompile instance rather than a
+/// process-global `static`, so the side-chain path stays deterministic and part
+/// of the context every node constructs identically. Callers that never use the
+/// side-chain flag can pass an empty store.
+pub fn verify_expander(blobs: CircuitBlobStore) -> PrecompileWithAddress {
+    PrecompileWithAddress(
+        crate::u64_to_address(0xff02),
+        Precompile::Stateful(Arc::new(VerifyExpander { blobs })),
+    )
+}
+
+/// Content-addressed registry of circuit/witness/proof blobs referenced by
+/// side-chain calldata, keyed by the keccak256 hash callers embed in their
+/// input. The execution environment populates it from a data-availability
+/// layer before running the block and hands it to [`verify_expander`], so it is
+/// part of the context every node shares rather than process-global state;
+/// resolving each hash against it keeps the side-chain path as deterministic
+/// as the inline one.
+pub type CircuitBlobStore = HashMap<B256, Bytes>;
+
+/// Stateful `verify_expander` precompile carrying the side-chain blob registry
+/// supplied by the execution environment.
+struct VerifyExpander {
+    blobs: CircuitBlobStore,
+}
+
+impl StatefulPrecompile for VerifyExpander {
+    fn call(&self, input: &Bytes, gas_limit: u64, _env: &Env) -> PrecompileResult {
+        verify_expander_run(input, gas_limit, &self.blobs)
+    }
+}
+
+/// Base cost charged for every invocation, independent of payload size.
+const BASE_GAS: u64 = 7500;
+/// Cost charged per byte of the source payload and, for compressed payloads,
+/// again per decompressed byte, accounting for the ABI decode and the witness
+/// allocation that scales with the payload.
+const GAS_PER_INPUT_BYTE: u64 = 3;
+/// Cost charged per circuit layer the verifier has to fold.
+const GAS_PER_LAYER: u64 = 100;
+/// Cost charged per gate summed across all layers of the circuit.
+const GAS_PER_GATE: u64 = 10;
+
+/// Selects the monomorphized proof-system parameters a payload was produced
+/// with. Encoded as the second header byte so new field/hash/scheme
+/// combinations can be deployed without a new precompile address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ProofSystem {
+    /// BN254 field, SHA2 transcript, vanilla GKR.
+    Bn254Sha2Vanilla,
+    /// BN254 field, SHA2 transcript, GKR^2 (squared) scheme.
+    Bn254Sha2Square,
+    /// BN254 field, Keccak transcript, vanilla GKR.
+    Bn254KeccakVanilla,
+    /// BN254 field, Keccak transcript, GKR^2 (squared) scheme.
+    Bn254KeccakSquare,
+}
+
+impl ProofSystem {
+    /// Decode the proof-system tag byte, rejecting unsupported combinations.
+    fn from_tag(tag: u8) -> Result<Self, PrecompileErrors> {
+        match tag {
+            0 => Ok(Self::Bn254Sha2Vanilla),
+            1 => Ok(Self::Bn254Sha2Square),
+            2 => Ok(Self::Bn254KeccakVanilla),
+            3 => Ok(Self::Bn254KeccakSquare),
+            _ => Err(PrecompileErrors::Error(PrecompileError::other(format!(
+                "verify expander unsupported proof system:{tag}"
+            )))),
+        }
+    }
+
+    /// The GKR scheme this combination runs.
+    fn scheme(&self) -> GKRScheme {
+        match self {
+            Self::Bn254Sha2Vanilla | Self::Bn254KeccakVanilla => GKRScheme::Vanilla,
+            Self::Bn254Sha2Square | Self::Bn254KeccakSquare => GKRScheme::GKRSquare,
+        }
+    }
+}
+
+/// Selects how the payload bytes are compressed, encoded as the third header
+/// byte. Raw passthrough lets callers submit tiny proofs without paying the
+/// decode gas, while deflate lets provers feed their serialized circuits
+/// directly without a redundant gzip wrapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    /// Uncompressed passthrough.
+    None,
+    /// gzip container.
+    Gzip,
+    /// Raw DEFLATE stream.
+    Deflate,
+}
+
+impl Compression {
+    /// Decode the compression tag byte, rejecting unsupported codecs.
+    fn from_tag(tag: u8) -> Result<Self, PrecompileErrors> {
+        match tag {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Gzip),
+            2 => Ok(Self::Deflate),
+            _ => Err(PrecompileErrors::Error(PrecompileError::other(format!(
+                "verify expander unsupported compression:{tag}"
+            )))),
+        }
+    }
+
+    /// Decompress `payload`, refusing to materialize more than `max_len` bytes
+    /// so a decompression bomb is rejected before it can exhaust memory. The
+    /// ceiling is derived from the gas the caller can still afford to pay per
+    /// decompressed byte.
+    fn decompress(&self, payload: &[u8], max_len: u64) -> Result<Vec<u8>, PrecompileErrors> {
+        // One byte of headroom lets us tell "exactly at the limit" apart from
+        // "overran the limit".
+        let limit = max_len.saturating_add(1);
+        let mut out = Vec::new();
+        let read = |reader: &mut dyn Read, out: &mut Vec<u8>| -> Result<(), PrecompileErrors> {
+            reader.take(limit).read_to_end(out).map(|_| ()).map_err(|e| {
+                PrecompileErrors::Error(PrecompileError::other(format!(
+                    "verify expander decompress error:{e}"
+                )))
+            })
+        };
+        match self {
+            Self::None => {
+                out.extend_from_slice(payload);
+            }
+            Self::Gzip => read(&mut GzDecoder::new(payload), &mut out)?,
+            Self::Deflate => read(&mut DeflateDecoder::new(payload), &mut out)?,
+        }
+        if out.len() as u64 > max_len {
+            return Err(PrecompileErrors::Error(PrecompileError::OutOfGas));
+        }
+        Ok(out)
+    }
+}
 
-const GAS: u64 = 7500;
+/// Total number of gates across every layer of the circuit. Used to price the
+/// verification work once the circuit dimensions are known, so the cost tracks
+/// the verifier's real per-gate effort and a huge circuit can't be verified as
+/// cheaply as a tiny one. Sums the four gate kinds each `CircuitLayer<C>` holds
+/// — `mul`, `add`, `const_` and `uni`; if upstream adds a gate family this
+/// needs a matching arm so the pricing doesn't undercount.
+fn circuit_gate_count<C: GKRConfig>(circuit: &Circuit<C>) -> u64 {
+    circuit
+        .layers
+        .iter()
+        .map(|layer| {
+            (layer.mul.len() + layer.add.len() + layer.const_.len() + layer.uni.len()) as u64
+        })
+        .sum()
+}
+
+/// Outcome of a verification, carrying everything the structured ABI output
+/// exposes to the caller.
+struct VerifyResult {
+    /// Whether the proof verified against the circuit.
+    ok: bool,
+    /// The circuit's public input, one field element per 32-byte word.
+    public_input: Vec<[u8; 32]>,
+    /// The serialized claimed value.
+    claimed_value: Vec<u8>,
+}
 
-pub fn verify_expander(input: &Bytes, gas_limit: u64) -> PrecompileResult {
-    if gas_limit < GAS {
+/// Run the GKR verifier for a concrete config `C`, charging the circuit-size
+/// portion of the gas along the way. Kept generic so every header-selected
+/// field/hash/scheme combination reuses the same verification path.
+fn verify_with_config<C>(
+    scheme: GKRScheme,
+    circuit_bytes: Vec<u8>,
+    witness_bytes: Vec<u8>,
+    proof_bytes: Vec<u8>,
+    gas_used: &mut u64,
+    gas_limit: u64,
+) -> Result<VerifyResult, PrecompileErrors>
+where
+    C: GKRConfig,
+    // The claimed value is deserialized and re-serialized generically, so the
+    // selected field must round-trip through serde — holds for both the Sha2
+    // and Keccak BN254 configs the header dispatches to.
+    C::ChallengeField: FieldSerde,
+    // `circuit.public_input` elements are serialized generically too; their
+    // field type needs the same bound so dispatch compiles for any config,
+    // including a future SIMD-packed one whose element isn't `ChallengeField`.
+    C::SimdCircuitField: FieldSerde,
+{
+    let mut circuit = Circuit::<C>::load_circuit_bytes(circuit_bytes).map_err(|e| {
+        PrecompileErrors::Error(PrecompileError::other(format!(
+            "load_circuit_bytes error:{e}"
+        )))
+    })?;
+
+    // Circuit dimensions are known now; charge for the verification work before
+    // loading the witness and running the verifier.
+    *gas_used = gas_used
+        .saturating_add(GAS_PER_LAYER.saturating_mul(circuit.layers.len() as u64))
+        .saturating_add(GAS_PER_GATE.saturating_mul(circuit_gate_count(&circuit)));
+    if gas_limit < *gas_used {
         return Err(PrecompileErrors::Error(PrecompileError::OutOfGas));
     }
-    let input = match input[0] {
-        0 => input[1..].to_vec(),
-        1 => todo!("get data from side chain"),
+
+    circuit.load_witness_bytes(&witness_bytes, false);
+
+    let config = Config::<C>::new(scheme, MPIConfig::new());
+    let verifier = Verifier::new(&config);
+
+    let mut cursor = Cursor::new(proof_bytes);
+    let proof = Proof::deserialize_from(&mut cursor).map_err(|e| {
+        PrecompileErrors::Error(PrecompileError::other(format!("format proof error:{e}")))
+    })?;
+    let claimed_v = C::ChallengeField::deserialize_from(&mut cursor).map_err(|e| {
+        PrecompileErrors::Error(PrecompileError::other(format!("format claimed error:{e}")))
+    })?;
+    let public_input = circuit.public_input.clone();
+    let ok = verifier.verify(&mut circuit, &public_input, &claimed_v, &proof);
+
+    // Serialize the public statement so the caller receives it alongside the
+    // verdict instead of having to re-supply it out of band.
+    let public_input = public_input
+        .iter()
+        .map(|x| {
+            let mut buf = Vec::new();
+            x.serialize_into(&mut buf).map_err(|e| {
+                PrecompileErrors::Error(PrecompileError::other(format!(
+                    "serialize public input error:{e}"
+                )))
+            })?;
+            // Each element must fit one 32-byte ABI word exactly; any other
+            // width (e.g. a future SIMD-packed config) would otherwise make
+            // `copy_from_slice` panic, so reject it with an error instead.
+            if buf.len() != 32 {
+                return Err(PrecompileErrors::Error(PrecompileError::other(format!(
+                    "verify expander public input width error:{}",
+                    buf.len()
+                ))));
+            }
+            let mut word = [0u8; 32];
+            word.copy_from_slice(&buf);
+            Ok(word)
+        })
+        .collect::<Result<Vec<_>, PrecompileErrors>>()?;
+
+    let mut claimed_value = Vec::new();
+    claimed_v.serialize_into(&mut claimed_value).map_err(|e| {
+        PrecompileErrors::Error(PrecompileError::other(format!(
+            "serialize claimed value error:{e}"
+        )))
+    })?;
+
+    Ok(VerifyResult {
+        ok,
+        public_input,
+        claimed_value,
+    })
+}
+
+/// Resolve a list of 32-byte keccak hashes against the side-chain blob
+/// registry supplied by the execution environment and concatenate the
+/// referenced bytes into the inline payload. Each retrieved blob is re-hashed
+/// and checked against the key it was looked up by, so a corrupt registry can
+/// never smuggle in bytes the caller did not commit to. A missing blob returns
+/// a distinct error so callers can retry with inline data.
+fn resolve_side_chain_blobs(
+    refs: &[u8],
+    store: &CircuitBlobStore,
+) -> Result<Vec<u8>, PrecompileErrors> {
+    if refs.is_empty() || refs.len() % 32 != 0 {
+        return Err(PrecompileErrors::Error(PrecompileError::other(
+            "verify expander side chain hash list format error",
+        )));
+    }
+
+    let mut resolved = Vec::new();
+    for chunk in refs.chunks(32) {
+        let key = B256::from_slice(chunk);
+        let blob = store.get(&key).ok_or_else(|| {
+            PrecompileErrors::Error(PrecompileError::other(format!(
+                "verify expander missing side chain blob:{key}"
+            )))
+        })?;
+        if keccak256(blob) != key {
+            return Err(PrecompileErrors::Error(PrecompileError::other(format!(
+                "verify expander side chain blob hash mismatch:{key}"
+            ))));
+        }
+        resolved.extend_from_slice(blob);
+    }
+
+    Ok(resolved)
+}
+
+fn verify_expander_run(
+    input: &Bytes,
+    gas_limit: u64,
+    blobs: &CircuitBlobStore,
+) -> PrecompileResult {
+    // Meter incrementally: start from the base cost and refuse up front if the
+    // caller cannot even afford that.
+    let mut gas_used = BASE_GAS;
+    if gas_limit < gas_used {
+        return Err(PrecompileErrors::Error(PrecompileError::OutOfGas));
+    }
+
+    // Header: byte[0] selects the data source, byte[1] the proof system and
+    // byte[2] the compression codec. Guard the fixed prefix up front so
+    // arbitrary short calldata returns an error instead of panicking.
+    if input.len() < 3 {
+        return Err(PrecompileErrors::Error(PrecompileError::other(
+            "verify expander input too short",
+        )));
+    }
+    let proof_system = ProofSystem::from_tag(input[1])?;
+    let compression = Compression::from_tag(input[2])?;
+    let payload = match input[0] {
+        0 => input[3..].to_vec(),
+        1 => resolve_side_chain_blobs(&input[3..], blobs)?,
         _ => {
             return Err(PrecompileErrors::Error(PrecompileError::Other(
                 String::from("data type format error"),
@@ -36,19 +344,37 @@ pub fn verify_expander(input: &Bytes, gas_limit: u64) -> PrecompileResult {
         }
     };
 
-    let input = {
-        let mut e = GzDecoder::new(Vec::new());
-        e.write_all(&input).map_err(|e| {
-            PrecompileErrors::Error(PrecompileError::other(format!(
-                "verify expander gzdecode write_all error:{e}"
-            )))
-        })?;
-        e.finish().map_err(|e| {
-            PrecompileErrors::Error(PrecompileError::other(format!(
-                "verify expander gzdecode finish error:{e}"
-            )))
-        })?
+    // Charge for the source payload volume before decompressing: the
+    // side-chain keccak re-hashing and the decoder read both scale with these
+    // bytes, so metering them keeps oversized inputs cheap to reject on the
+    // side-chain path too.
+    gas_used = gas_used.saturating_add(GAS_PER_INPUT_BYTE.saturating_mul(payload.len() as u64));
+    if gas_limit < gas_used {
+        return Err(PrecompileErrors::Error(PrecompileError::OutOfGas));
+    }
+
+    // Bound the decompressed size by the gas the caller can still pay for it,
+    // so oversized inputs and decompression bombs are rejected cheaply. Raw
+    // passthrough only copies the already-paid source bytes, so its ceiling is
+    // the payload length itself.
+    let max_decompressed = match compression {
+        Compression::None => payload.len() as u64,
+        _ => (gas_limit - gas_used) / GAS_PER_INPUT_BYTE,
     };
+    let input = compression.decompress(&payload, max_decompressed)?;
+    // Charge for the bytes the decoder produced on top of the source payload,
+    // but only for codecs that actually expand: raw passthrough copies the
+    // already-metered source bytes, so charging them again would price a raw
+    // proof at twice the gas of a gzip one and defeat the skip-compression
+    // escape hatch.
+    if compression != Compression::None {
+        gas_used =
+            gas_used.saturating_add(GAS_PER_INPUT_BYTE.saturating_mul(input.len() as u64));
+        if gas_limit < gas_used {
+            return Err(PrecompileErrors::Error(PrecompileError::OutOfGas));
+        }
+    }
+
     let tokens = ethabi::decode(
         &[ParamType::Tuple(vec![
             ParamType::Bytes,
@@ -95,31 +421,43 @@ pub fn verify_expander(input: &Bytes, gas_limit: u64) -> PrecompileResult {
             "verify expander proof format error",
         )))?;
 
-    let mut circuit =
-        Circuit::<BN254ConfigSha2>::load_circuit_bytes(circuit_bytes).map_err(|e| {
-            PrecompileErrors::Error(PrecompileError::other(format!(
-                "load_circuit_bytes error:{e}"
-            )))
-        })?;
-
-    circuit.load_witness_bytes(&witness_bytes, false);
-
-    let config = Config::<BN254ConfigSha2>::new(GKRScheme::Vanilla, MPIConfig::new());
-    let verifier = Verifier::new(&config);
-
-    let mut cursor = Cursor::new(proof_bytes);
-    let proof = Proof::deserialize_from(&mut cursor).map_err(|e| {
-        PrecompileErrors::Error(PrecompileError::other(format!("format proof error:{e}")))
-    })?;
-    let claimed_v = Fr::deserialize_from(&mut cursor).map_err(|e| {
-        PrecompileErrors::Error(PrecompileError::other(format!("format claimed error:{e}")))
-    })?;
-    let public_input = circuit.public_input.clone();
-    let bytes = if verifier.verify(&mut circuit, &public_input, &claimed_v, &proof) {
-        "y".as_bytes().to_vec()
-    } else {
-        "n".as_bytes().to_vec()
+    // Dispatch to the monomorphized verifier selected by the header.
+    let scheme = proof_system.scheme();
+    let result = match proof_system {
+        ProofSystem::Bn254Sha2Vanilla | ProofSystem::Bn254Sha2Square => {
+            verify_with_config::<BN254ConfigSha2>(
+                scheme,
+                circuit_bytes,
+                witness_bytes,
+                proof_bytes,
+                &mut gas_used,
+                gas_limit,
+            )?
+        }
+        ProofSystem::Bn254KeccakVanilla | ProofSystem::Bn254KeccakSquare => {
+            verify_with_config::<BN254ConfigKeccak>(
+                scheme,
+                circuit_bytes,
+                witness_bytes,
+                proof_bytes,
+                &mut gas_used,
+                gas_limit,
+            )?
+        }
     };
 
-    Ok(PrecompileOutput::new(GAS, bytes.into()))
+    // Return the verdict bound to the public statement it authorizes:
+    // (bool ok, bytes32[] publicInput, bytes claimedValue).
+    let public_input = result
+        .public_input
+        .into_iter()
+        .map(|word| Token::FixedBytes(word.to_vec()))
+        .collect();
+    let output = ethabi::encode(&[
+        Token::Bool(result.ok),
+        Token::Array(public_input),
+        Token::Bytes(result.claimed_value),
+    ]);
+
+    Ok(PrecompileOutput::new(gas_used, output.into()))
 }